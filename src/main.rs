@@ -3,28 +3,166 @@ use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
 use image::ImageReader;
 use image::DynamicImage;
 use rexif;
+use blake3;
+use clap::Parser;
+use filetime::{set_file_mtime, FileTime};
+use img_parts::avif::Avif;
+use img_parts::jpeg::Jpeg;
+use img_parts::{Bytes, ImageEXIF};
 use rayon::prelude::*;
 use ravif::{Encoder, Img, RGBA8};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::{Arc, Mutex};
 use walkdir::WalkDir;
 
+/// 按目标路径分发的锁表：同一个目标文件的检查+编码+写入必须串行执行，
+/// 否则两个源文件算出同一个 `base_filename` 时会在 rayon 并行下互相覆盖
+type PathLocks = Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>;
+
+/// 取出（或创建）某个目标路径专属的锁，用于串行化对它的检查与写入
+fn lock_for_path(path_locks: &PathLocks, path: &Path) -> Arc<Mutex<()>> {
+    path_locks
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone()
+}
+
 // 支持的图片扩展名
 const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tiff"];
 
+// TODO(架构问题，未解决): 最初的需求是让 exiftool 兜底支持视频 (MOV/MP4) 和 HEIC 的拍摄时间读取，
+// 但 decode_pixels 目前只能处理 IMAGE_EXTENSIONS / RAW_IMAGE_EXTENSIONS 覆盖的格式——
+// image 库不解码 HEIC，也没有视频帧提取的管线，所以这些文件即使读到了拍摄时间也无法转换成 AVIF。
+// 在这个问题被重新立项、决定要不要引入 HEIC/视频解码依赖之前，这里只负责让用户看到它们被跳过了，
+// 而不是像过去那样被 WalkDir 过滤器悄悄吞掉
+const UNSUPPORTED_EXTENSIONS: &[&str] = &["heic", "heif", "mov", "mp4"];
+
+// 支持的 RAW 相机格式扩展名，需要先经过 rawloader + imagepipe 解码
+const RAW_IMAGE_EXTENSIONS: &[&str] = &[
+    "cr2", "nef", "arw", "dng", "raf", "orf", "rw2", "pef", "srw", "3fr",
+];
+
+/// 判断扩展名是否属于 RAW 相机格式
+fn is_raw_extension(ext: &str) -> bool {
+    RAW_IMAGE_EXTENSIONS.contains(&ext)
+}
+
+/// 拍摄时间的来源，数值越靠后代表可信度越低
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DatetimeOrigin {
+    /// rexif 成功解析出的 EXIF DateTimeOriginal
+    Exif,
+    /// rexif 无法解析时，回退到 exiftool 子进程读到的 CreateDate
+    ExifTool,
+    /// 前两者都不可用，只能使用文件系统的创建/修改时间
+    FilesystemMetadata,
+}
+
+impl std::fmt::Display for DatetimeOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            DatetimeOrigin::Exif => "EXIF",
+            DatetimeOrigin::ExifTool => "exiftool",
+            DatetimeOrigin::FilesystemMetadata => "文件系统时间",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// exiftool 的 `-j` 输出里我们关心的字段
+#[derive(serde::Deserialize)]
+struct ExifToolEntry {
+    #[serde(rename = "CreateDate")]
+    create_date: Option<String>,
+}
+
+/// chrono_avif：批量把照片转换为 AVIF，并按拍摄时间归档
+#[derive(Parser, Debug)]
+#[command(name = "chrono_avif", version, about)]
+struct Cli {
+    /// 要处理的目标目录，默认为当前工作目录
+    #[arg(default_value = ".")]
+    directory: PathBuf,
+
+    /// 图库模式：将转换结果按 `ROOT/YYYY/MM` 归档到该目录，而不是写在原文件夹
+    #[arg(long, value_name = "ROOT")]
+    library: Option<PathBuf>,
+
+    /// 转换成功后保留原文件，不删除
+    #[arg(long)]
+    keep_originals: bool,
+
+    /// 只打印将要执行的转换/删除操作，不实际写入或删除任何文件
+    #[arg(long)]
+    dry_run: bool,
+
+    /// AVIF 编码质量，范围 0-100
+    #[arg(long, default_value_t = 80.0)]
+    quality: f32,
+
+    /// AVIF 编码速度，范围 0-10，数字越大速度越快、质量越低
+    #[arg(long, default_value_t = 6)]
+    speed: u8,
+
+    /// rayon 并行线程数，默认使用 CPU 核心数
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+/// 贯穿整个运行期的转换选项，避免每个函数都堆一串独立参数
+struct ConvertOptions {
+    library_root: Option<PathBuf>,
+    keep_originals: bool,
+    dry_run: bool,
+    quality: f32,
+    speed: u8,
+}
+
 fn main() -> Result<()> {
-    // 获取当前工作目录（程序运行的目录）
-    let current_dir = std::env::current_dir()
-        .context("无法获取当前工作目录")?;
-    
+    let cli = Cli::parse();
+
+    // 配置 rayon 全局线程池，默认使用 CPU 核心数
+    let threads = cli.threads.unwrap_or_else(num_cpus::get);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .context("无法配置 rayon 线程池")?;
+
+    let target_dir = cli.directory;
+    let options = ConvertOptions {
+        library_root: cli.library,
+        keep_originals: cli.keep_originals,
+        dry_run: cli.dry_run,
+        quality: cli.quality,
+        speed: cli.speed,
+    };
+
     println!("🚀 开始处理图片文件...");
-    println!("📁 处理目录: {}", current_dir.display());
-    println!("⚠️  注意：转换后的文件将保存在原文件夹，原图将被删除");
+    println!("📁 处理目录: {}", target_dir.display());
+    println!("🧵 并行线程数: {}", threads);
+    if let Some(root) = &options.library_root {
+        println!("🗂️  图库模式已开启，输出目录: {}", root.display());
+    } else {
+        println!(
+            "⚠️  注意：转换后的文件将保存在原文件夹{}",
+            if options.keep_originals || options.dry_run { "" } else { "，原图将被删除" }
+        );
+    }
+    if options.keep_originals {
+        println!("🛡️  --keep-originals 已开启，原文件不会被删除");
+    }
+    if options.dry_run {
+        println!("🧪 --dry-run 已开启，不会写入或删除任何文件");
+    }
     println!("📂 将递归处理当前目录及其所有子目录\n");
 
     // 收集所有图片文件（只处理当前目录及其子目录）
-    let image_files: Vec<PathBuf> = WalkDir::new(&current_dir)
+    let image_files: Vec<PathBuf> = WalkDir::new(&target_dir)
         .into_iter()
         .filter_map(|e| e.ok())
         .filter(|e| {
@@ -32,12 +170,35 @@ fn main() -> Result<()> {
                 && e.path()
                     .extension()
                     .and_then(|ext| ext.to_str())
-                    .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                    .map(|ext| {
+                        let ext = ext.to_lowercase();
+                        IMAGE_EXTENSIONS.contains(&ext.as_str()) || is_raw_extension(&ext)
+                    })
                     .unwrap_or(false)
         })
         .map(|e| e.path().to_path_buf())
         .collect();
 
+    // 视频/HEIC 目前解码不了，但不能让它们被过滤器默默吞掉——明确告诉用户跳过了哪些、为什么
+    let unsupported_count = WalkDir::new(&target_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_type().is_file()
+                && e.path()
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| UNSUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                    .unwrap_or(false)
+        })
+        .count();
+    if unsupported_count > 0 {
+        println!(
+            "⚠️  发现 {} 个视频/HEIC 文件，目前暂不支持解码，已跳过（见 UNSUPPORTED_EXTENSIONS 旁的 TODO）",
+            unsupported_count
+        );
+    }
+
     let total = image_files.len();
     println!("📸 找到 {} 个图片文件", total);
 
@@ -49,20 +210,32 @@ fn main() -> Result<()> {
     // 用于统计进度
     let processed: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
     let deleted: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
+    // 图库模式下多个线程可能同时为同一个 年/月 目录调用 create_dir_all，用锁避免竞争
+    let dir_creation_lock: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    // 两个源文件可能算出同一个目标路径（同一个 base_filename），用锁串行化对它的检查+写入
+    let path_locks: Arc<PathLocks> = Arc::new(Mutex::new(HashMap::new()));
 
     // 使用 rayon 并行处理所有图片
     let results: Vec<Result<()>> = image_files
         .par_iter()
         .map(|image_path| {
-            process_image(image_path, &processed, &deleted, total)
+            process_image(
+                image_path,
+                &options,
+                &dir_creation_lock,
+                &path_locks,
+                &processed,
+                &deleted,
+                total,
+            )
         })
         .collect();
 
     // 检查是否有错误
     let errors: Vec<_> = results.into_iter().filter_map(|r| r.err()).collect();
-    
+
     let deleted_count = *deleted.lock().unwrap();
-    
+
     if !errors.is_empty() {
         eprintln!("\n❌ 处理过程中遇到 {} 个错误:", errors.len());
         for err in &errors {
@@ -79,6 +252,9 @@ fn main() -> Result<()> {
 /// 处理单个图片文件
 fn process_image(
     image_path: &Path,
+    options: &ConvertOptions,
+    dir_creation_lock: &Arc<Mutex<()>>,
+    path_locks: &Arc<PathLocks>,
     processed: &Arc<Mutex<usize>>,
     deleted: &Arc<Mutex<usize>>,
     total: usize,
@@ -87,69 +263,270 @@ fn process_image(
     let parent_dir = image_path.parent()
         .ok_or_else(|| anyhow::anyhow!("无法获取文件目录"))?;
 
-    // 获取拍摄时间
-    let datetime = get_image_datetime(image_path)
+    // 获取拍摄时间（以及它的来源，来源越弱越应该让用户知道）
+    let (datetime, datetime_origin) = get_image_datetime(image_path)
         .with_context(|| format!("无法获取图片时间: {}", image_path.display()))?;
 
     // 格式化时间为目标文件名格式：YYYY年MM月DD日 HH-mm-ss
     let formatted_time = datetime.format("%Y年%m月%d日 %H-%M-%S").to_string();
-    
+
     // 生成基础文件名
     let base_filename = format!("{}.avif", formatted_time);
-    
-    // 处理文件名冲突（在原目录中检查）
-    let final_filename = generate_unique_filename(
-        parent_dir,
-        &base_filename,
-    )?;
 
-    let output_path = parent_dir.join(&final_filename);
+    if options.dry_run {
+        // dry-run 只计算路径，不创建目录、不解码、不写入、不删除
+        let target_dir = match &options.library_root {
+            Some(root) => library_dir_path(root, &datetime),
+            None => parent_dir.to_path_buf(),
+        };
+        let output_path = target_dir.join(&base_filename);
 
-    // 读取并转换图片为 AVIF
-    convert_to_avif(image_path, &output_path)
-        .with_context(|| format!("转换失败: {} -> {}", image_path.display(), output_path.display()))?;
+        let mut count = processed.lock().unwrap();
+        *count += 1;
+        println!(
+            "[{}/{}] 🧪 (dry-run) {} -> {}{}",
+            *count,
+            total,
+            image_path.display(),
+            output_path.display(),
+            if options.keep_originals { "" } else { "，之后会删除原图" }
+        );
+        return Ok(());
+    }
 
-    // 删除原文件
-    fs::remove_file(image_path)
-        .with_context(|| format!("无法删除原文件: {}", image_path.display()))?;
+    // 图库模式下按拍摄时间的 年/月 归档，否则保持在原目录
+    let target_dir = match &options.library_root {
+        Some(root) => find_backup_dir(root, &datetime, dir_creation_lock)?,
+        None => parent_dir.to_path_buf(),
+    };
+
+    // 转换图片为 AVIF，内部会检查同名文件是否已经是同一张图（哈希比对）
+    let outcome = convert_with_safety_check(image_path, &target_dir, &base_filename, &datetime, options, path_locks)
+        .with_context(|| format!("转换失败: {} -> {}", image_path.display(), target_dir.join(&base_filename).display()))?;
+
+    let (final_path, status_label) = match &outcome {
+        ConversionOutcome::Converted(path) => {
+            let label = if options.keep_originals { "已保留原图" } else { "已删除原图" };
+            (path.clone(), label.to_string())
+        }
+        ConversionOutcome::SkippedDuplicate(existing_path) => {
+            (existing_path.clone(), "重复文件，已跳过".to_string())
+        }
+        ConversionOutcome::AlreadyBackupButDifferent(path) => {
+            (path.clone(), "⚠️ 同名文件内容不同，已保留两者".to_string())
+        }
+    };
+
+    // 只有确认输出文件真实存在且非空时才删除原文件；--keep-originals 时完全不删除
+    let deleted_source = if options.keep_originals {
+        false
+    } else {
+        verify_output_written(&final_path)
+            .with_context(|| format!("转换后的文件校验失败: {}", final_path.display()))?;
+        fs::remove_file(image_path)
+            .with_context(|| format!("无法删除原文件: {}", image_path.display()))?;
+        true
+    };
 
     // 更新进度
     let mut count = processed.lock().unwrap();
     *count += 1;
-    let mut del_count = deleted.lock().unwrap();
-    *del_count += 1;
-    
+    if deleted_source {
+        let mut del_count = deleted.lock().unwrap();
+        *del_count += 1;
+    }
+
     println!(
-        "[{}/{}] ✅ {} -> {} (已删除原图)",
+        "[{}/{}] ✅ {} -> {} ({}, 时间来源: {})",
         *count,
         total,
         image_path.file_name().unwrap_or_default().to_string_lossy(),
-        final_filename
+        final_path.file_name().unwrap_or_default().to_string_lossy(),
+        status_label,
+        datetime_origin
     );
 
     Ok(())
 }
 
+/// 转换结果：正常转换、发现重复（跳过）、或同名但内容不同（两者都保留）
+enum ConversionOutcome {
+    Converted(PathBuf),
+    SkippedDuplicate(PathBuf),
+    AlreadyBackupButDifferent(PathBuf),
+}
+
+/// 将图片转换为 AVIF，并在写入前判断目标目录中是否已经有“看起来一样”的文件
+/// 参考 picobak 的 "already backed up but different" 思路：同名不代表同内容
+fn convert_with_safety_check(
+    image_path: &Path,
+    target_dir: &Path,
+    base_filename: &str,
+    datetime: &DateTime<Local>,
+    options: &ConvertOptions,
+    path_locks: &Arc<PathLocks>,
+) -> Result<ConversionOutcome> {
+    let base_path = target_dir.join(base_filename);
+    // 哈希的是源文件本身的字节，而不是解码后再从有损 AVIF 里解码回来的像素——
+    // AVIF 编码是有损的，重新解码绝大多数时候都对不上同一张源图的像素哈希
+    let source_hash = hash_source_file(image_path)?;
+
+    // 两个源文件可能算出同一个 base_path（比如同一秒拍摄的照片），
+    // 必须把“检查是否存在 -> 决定跳过/改名 -> 写入”这一整段串行化，
+    // 否则 rayon 并行下两个线程都会看到 exists() == false 并互相覆盖对方的输出
+    let dest_lock = lock_for_path(path_locks, &base_path);
+    let _guard = dest_lock.lock().unwrap();
+
+    if base_path.exists() {
+        if is_duplicate_of(read_hash_sidecar(&base_path), source_hash) {
+            // 源文件字节和上次写出这个目标文件时记录的哈希一致，确实是同一张图
+            return Ok(ConversionOutcome::SkippedDuplicate(base_path));
+        }
+
+        // 同名但内容不同（或者没有哈希记录可比对，保守起见也当作不同），生成新名字，两个文件都保留
+        let (pixels, width, height) = decode_pixels(image_path)?;
+        let alt_filename = generate_unique_filename(target_dir, base_filename)?;
+        let alt_path = target_dir.join(&alt_filename);
+        encode_and_write_avif(&pixels, width, height, &alt_path, options.quality, options.speed)?;
+        write_hash_sidecar(&alt_path, &source_hash)?;
+        preserve_capture_metadata(image_path, &alt_path, datetime)?;
+        return Ok(ConversionOutcome::AlreadyBackupButDifferent(alt_path));
+    }
+
+    let (pixels, width, height) = decode_pixels(image_path)?;
+    encode_and_write_avif(&pixels, width, height, &base_path, options.quality, options.speed)?;
+    write_hash_sidecar(&base_path, &source_hash)?;
+    preserve_capture_metadata(image_path, &base_path, datetime)?;
+    Ok(ConversionOutcome::Converted(base_path))
+}
+
+/// 判断一个已存在的输出文件是否真的是同一张源图：
+/// 只有当 sidecar 里记录着哈希、且与当前源文件哈希一致时才算重复；
+/// 没有 sidecar（比如旧版本产物）或哈希对不上，一律保守地当作"不是同一张图"
+fn is_duplicate_of(existing_hash: Option<blake3::Hash>, candidate_hash: blake3::Hash) -> bool {
+    existing_hash == Some(candidate_hash)
+}
+
+/// 对源文件的原始字节做哈希，用于在有损编码之后仍能判断"是不是同一张图"
+fn hash_source_file(source_path: &Path) -> Result<blake3::Hash> {
+    let data = fs::read(source_path)
+        .with_context(|| format!("无法读取文件用于计算哈希: {}", source_path.display()))?;
+    Ok(blake3::hash(&data))
+}
+
+/// 输出文件旁边记录源文件哈希的 sidecar 文件路径，例如 `foo.avif.srchash`
+fn hash_sidecar_path(output_path: &Path) -> PathBuf {
+    let mut name = output_path.as_os_str().to_os_string();
+    name.push(".srchash");
+    PathBuf::from(name)
+}
+
+/// 读取 sidecar 中记录的源文件哈希，不存在或格式不对时返回 None
+fn read_hash_sidecar(output_path: &Path) -> Option<blake3::Hash> {
+    let content = fs::read_to_string(hash_sidecar_path(output_path)).ok()?;
+    blake3::Hash::from_hex(content.trim()).ok()
+}
+
+/// 把源文件哈希写入 sidecar，供下次运行判断同名输出是否真的是同一张图
+fn write_hash_sidecar(output_path: &Path, hash: &blake3::Hash) -> Result<()> {
+    let sidecar_path = hash_sidecar_path(output_path);
+    fs::write(&sidecar_path, hash.to_hex().to_string())
+        .with_context(|| format!("无法写入哈希校验文件: {}", sidecar_path.display()))
+}
+
+/// 转换后把拍摄时间和原始 EXIF 写回输出文件，保证按文件名排序和按元数据排序结果一致
+/// (1) 把文件的 mtime 设置成拍摄时间 (2) 把原图的 EXIF 块重新嵌入 AVIF 容器
+fn preserve_capture_metadata(
+    source_path: &Path,
+    output_path: &Path,
+    datetime: &DateTime<Local>,
+) -> Result<()> {
+    // 先嵌入 EXIF 再设置 mtime：embed_exif_into_avif 内部会整体重写文件，
+    // 如果顺序反过来，重写会把 mtime 重置成“转换发生的时间”，而不是拍摄时间
+    match extract_exif_block(source_path) {
+        Some(exif) => {
+            if let Err(err) = embed_exif_into_avif(output_path, exif) {
+                eprintln!("⚠️  无法向 {} 写入 EXIF: {}", output_path.display(), err);
+            }
+        }
+        None => {
+            // extract_exif_block 目前只认识 JPEG 容器，非 JPEG 来源（RAW/PNG/TIFF）或本来就没有 EXIF 的 JPEG
+            // 的相机型号/GPS/朝向等信息无法保留，需要让用户知道这一点，而不是悄悄丢掉
+            println!(
+                "ℹ️  {} 没有可提取的 EXIF（目前只从 JPEG 提取），输出文件不会带相机型号/GPS/朝向信息",
+                source_path.display()
+            );
+        }
+    }
+
+    // 无论 EXIF 嵌入是否成功，都要在最后把 mtime 设置成拍摄时间
+    let mtime = FileTime::from_unix_time(datetime.timestamp(), 0);
+    set_file_mtime(output_path, mtime)
+        .with_context(|| format!("无法设置输出文件的修改时间: {}", output_path.display()))?;
+
+    Ok(())
+}
+
+/// 从原始图片中提取原始 EXIF 字节块（目前仅支持 JPEG 容器，RAW 暂不支持）
+fn extract_exif_block(source_path: &Path) -> Option<Bytes> {
+    let data = fs::read(source_path).ok()?;
+    Jpeg::from_bytes(data.into()).ok()?.exif()
+}
+
+/// 把 EXIF 字节块重新写入已经编码好的 AVIF 容器
+fn embed_exif_into_avif(avif_path: &Path, exif: Bytes) -> Result<()> {
+    let data = fs::read(avif_path)
+        .with_context(|| format!("无法读取 AVIF 文件: {}", avif_path.display()))?;
+
+    let mut avif = Avif::from_bytes(data.into())
+        .map_err(|e| anyhow::anyhow!("无法解析生成的 AVIF 容器: {}", e))?;
+    avif.set_exif(Some(exif));
+
+    fs::write(avif_path, avif.encoder().bytes())
+        .with_context(|| format!("无法写回 AVIF 文件: {}", avif_path.display()))?;
+
+    Ok(())
+}
+
+/// 确认输出文件确实写入成功且非空，避免删除原图后发现输出是空文件/半成品
+fn verify_output_written(output_path: &Path) -> Result<()> {
+    let metadata = fs::metadata(output_path)
+        .with_context(|| format!("输出文件不存在: {}", output_path.display()))?;
+
+    if metadata.len() == 0 {
+        return Err(anyhow::anyhow!("输出文件为空: {}", output_path.display()));
+    }
+
+    Ok(())
+}
+
 /// 获取图片的拍摄时间
-/// 优先级：1. EXIF DateTimeOriginal  2. 文件系统创建时间
-fn get_image_datetime(image_path: &Path) -> Result<DateTime<Local>> {
+/// 优先级：1. EXIF DateTimeOriginal  2. exiftool 子进程  3. 文件系统创建时间
+fn get_image_datetime(image_path: &Path) -> Result<(DateTime<Local>, DatetimeOrigin)> {
     // 尝试从 EXIF 读取 DateTimeOriginal
     if let Ok(datetime) = get_exif_datetime(image_path) {
-        return Ok(datetime);
+        return Ok((datetime, DatetimeOrigin::Exif));
     }
 
-    // 如果 EXIF 不存在，使用文件系统元数据
+    // rexif 对某些 JPEG/TIFF/RAW 变体解析不了时，尝试调用 exiftool 作为第二道解析手段
+    // 注意：这不会让工具支持视频或 HEIC —— 那些格式根本没进入 IMAGE_EXTENSIONS / RAW_IMAGE_EXTENSIONS
+    // 白名单，decode_pixels 也无法解码它们，所以它们仍然被 WalkDir 过滤掉，不会走到这里
+    if let Ok(datetime) = get_exiftool_datetime(image_path) {
+        return Ok((datetime, DatetimeOrigin::ExifTool));
+    }
+
+    // 如果都不可用，使用文件系统元数据
     let metadata = fs::metadata(image_path)
         .context("无法读取文件元数据")?;
-    
+
     // 优先使用创建时间，如果没有则使用修改时间
     let system_time = metadata
         .created()
         .or_else(|_| metadata.modified())
         .context("无法获取文件时间")?;
-    
+
     let datetime: DateTime<Local> = system_time.into();
-    Ok(datetime)
+    Ok((datetime, DatetimeOrigin::FilesystemMetadata))
 }
 
 /// 从 EXIF 元数据中读取 DateTimeOriginal
@@ -178,6 +555,62 @@ fn get_exif_datetime(image_path: &Path) -> Result<DateTime<Local>> {
     Err(anyhow::anyhow!("EXIF 中未找到 DateTimeOriginal"))
 }
 
+/// 当 rexif 无法解析已支持格式（JPEG/PNG/TIFF/RAW）的 EXIF 时，
+/// 回退到 exiftool 子进程读取 CreateDate。
+/// 视频（MOV/MP4）和 HEIC 不在本工具处理范围内：它们不在扩展名白名单里，
+/// 也没有对应的解码路径，所以根本不会走到这个函数
+fn get_exiftool_datetime(image_path: &Path) -> Result<DateTime<Local>> {
+    let output = Command::new("exiftool")
+        .arg("-j")
+        .arg("-CreateDate")
+        .arg(image_path)
+        .output()
+        .context("无法执行 exiftool（可能未安装）")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("exiftool 执行失败"));
+    }
+
+    let entries: Vec<ExifToolEntry> = serde_json::from_slice(&output.stdout)
+        .context("无法解析 exiftool 输出")?;
+
+    let create_date = entries
+        .into_iter()
+        .next()
+        .and_then(|entry| entry.create_date)
+        .ok_or_else(|| anyhow::anyhow!("exiftool 输出中未找到 CreateDate"))?;
+
+    let naive_dt = NaiveDateTime::parse_from_str(&create_date, "%Y:%m:%d %H:%M:%S")
+        .context("无法解析 exiftool 返回的时间格式")?;
+
+    Local
+        .from_local_datetime(&naive_dt)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("无效的时区转换"))
+}
+
+/// 根据拍摄时间计算图库目录路径（`root/YYYY/MM`），不做任何磁盘操作
+fn library_dir_path(root: &Path, datetime: &DateTime<Local>) -> PathBuf {
+    root.join(datetime.format("%Y").to_string())
+        .join(datetime.format("%m").to_string())
+}
+
+/// 根据拍摄时间计算图库目录（`root/YYYY/MM`）并确保其存在
+/// 多个线程可能同时为同一个年月目录调用 create_dir_all，用锁避免竞争
+fn find_backup_dir(
+    root: &Path,
+    datetime: &DateTime<Local>,
+    dir_creation_lock: &Arc<Mutex<()>>,
+) -> Result<PathBuf> {
+    let dir = library_dir_path(root, datetime);
+
+    let _guard = dir_creation_lock.lock().unwrap();
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("无法创建图库目录: {}", dir.display()))?;
+
+    Ok(dir)
+}
+
 /// 生成唯一的文件名，处理冲突（检查目录中是否已存在同名文件）
 fn generate_unique_filename(
     parent_dir: &Path,
@@ -211,29 +644,75 @@ fn generate_unique_filename(
     }
 }
 
-/// 将图片转换为 AVIF 格式（使用纯 Rust 的 ravif 库）
-fn convert_to_avif(input_path: &Path, output_path: &Path) -> Result<()> {
-    // 使用 image 库读取图片
-    let img: DynamicImage = ImageReader::open(input_path)
-        .context("无法打开图片文件")?
-        .decode()
-        .context("无法解码图片")?;
+/// 读取 RAW 相机文件（CR2/NEF/ARW/DNG 等）并解码为 RGBA8 像素缓冲区
+fn decode_raw_image(input_path: &Path) -> Result<(Vec<u8>, u32, u32)> {
+    let raw_image = rawloader::decode_file(input_path)
+        .map_err(|e| anyhow::anyhow!("无法解析 RAW 文件: {}", e))?;
+
+    let source = imagepipe::ImageSource::Raw(raw_image);
+    let mut pipeline = imagepipe::Pipeline::new_from_source(source)
+        .map_err(|e| anyhow::anyhow!("无法创建 RAW 解码流水线: {}", e))?;
+
+    let decoded = pipeline
+        .output_8bit(None)
+        .map_err(|e| anyhow::anyhow!("RAW 解码失败: {}", e))?;
+
+    let width = decoded.width as u32;
+    let height = decoded.height as u32;
+
+    // imagepipe 输出的是 8-bit RGB，需要补上 alpha 通道转成 RGBA8
+    let mut rgba = Vec::with_capacity(decoded.data.len() / 3 * 4);
+    for chunk in decoded.data.chunks_exact(3) {
+        rgba.extend_from_slice(chunk);
+        rgba.push(255);
+    }
+
+    Ok((rgba, width, height))
+}
+
+/// 读取任意支持的输入文件，返回 RGBA8 像素缓冲区及宽高（RAW 走独立解码路径）
+fn decode_pixels(input_path: &Path) -> Result<(Vec<u8>, u32, u32)> {
+    let is_raw = input_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| is_raw_extension(&ext.to_lowercase()))
+        .unwrap_or(false);
 
-    // 将图像转换为 RGBA8 格式（ravif 需要 RGBA）
-    let rgba_img = img.to_rgba8();
-    let (width, height) = rgba_img.dimensions();
+    // RAW 相机文件走 rawloader + imagepipe 解码，其余格式沿用 image 库
+    if is_raw {
+        decode_raw_image(input_path)
+    } else {
+        let img: DynamicImage = ImageReader::open(input_path)
+            .context("无法打开图片文件")?
+            .decode()
+            .context("无法解码图片")?;
 
+        // 将图像转换为 RGBA8 格式（ravif 需要 RGBA）
+        let rgba_img = img.to_rgba8();
+        let (width, height) = rgba_img.dimensions();
+        Ok((rgba_img.into_raw(), width, height))
+    }
+}
+
+/// 将 RGBA8 像素缓冲区编码为 AVIF 并写入磁盘（使用纯 Rust 的 ravif 库）
+fn encode_and_write_avif(
+    pixels_u8: &[u8],
+    width: u32,
+    height: u32,
+    output_path: &Path,
+    quality: f32,
+    speed: u8,
+) -> Result<()> {
     // 配置 AVIF 编码参数
-    // speed: 6 (平衡编码速度和质量，范围 0-10，数字越大速度越快)
-    // quality: 80 (高质量，范围 0-100)
+    // speed: 0-10，数字越大速度越快
+    // quality: 0-100，数字越大质量越高
     let encoder = Encoder::new()
-        .with_quality(80.0)
-        .with_speed(6);
+        .with_quality(quality)
+        .with_speed(speed);
 
     // 编码为 AVIF
     // ravif 需要 Img<&[RGBA8]> 格式
     // 将 &[u8] 转换为 &[RGBA8]
-    let pixels_u8 = rgba_img.as_raw();
     let pixels_rgba: &[RGBA8] = unsafe {
         std::slice::from_raw_parts(
             pixels_u8.as_ptr() as *const RGBA8,
@@ -251,3 +730,77 @@ fn convert_to_avif(input_path: &Path, output_path: &Path) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // 每个测试用独立的临时目录，避免并行测试互相踩文件
+    fn unique_test_dir(label: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!(
+            "chrono_avif_test_{}_{}_{}",
+            std::process::id(),
+            label,
+            n
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn is_duplicate_of_matches_only_when_hash_matches() {
+        let hash_a = blake3::hash(b"photo a");
+        let hash_b = blake3::hash(b"photo b");
+
+        assert!(is_duplicate_of(Some(hash_a), hash_a));
+        assert!(!is_duplicate_of(Some(hash_a), hash_b));
+        // 没有 sidecar 记录时，保守地当作不是同一张图，绝不能默认跳过
+        assert!(!is_duplicate_of(None, hash_a));
+    }
+
+    #[test]
+    fn read_hash_sidecar_returns_none_when_missing() {
+        let dir = unique_test_dir("missing_sidecar");
+        let output_path = dir.join("photo.avif");
+        // 只写了 AVIF 本体，没有写 sidecar（比如旧版本产物或手动放进去的文件）
+        fs::write(&output_path, b"fake avif bytes").unwrap();
+
+        assert_eq!(read_hash_sidecar(&output_path), None);
+    }
+
+    #[test]
+    fn hash_sidecar_roundtrip_detects_same_and_different_source() {
+        let dir = unique_test_dir("sidecar_roundtrip");
+        let output_path = dir.join("photo.avif");
+        fs::write(&output_path, b"fake avif bytes").unwrap();
+
+        let original_hash = blake3::hash(b"original source bytes");
+        write_hash_sidecar(&output_path, &original_hash).unwrap();
+
+        // 重复：同一份源文件再跑一次，哈希应该对得上
+        assert!(is_duplicate_of(read_hash_sidecar(&output_path), original_hash));
+
+        // 冲突：同名输出，但这次的源文件字节不一样
+        let different_hash = blake3::hash(b"a completely different photo");
+        assert!(!is_duplicate_of(read_hash_sidecar(&output_path), different_hash));
+    }
+
+    #[test]
+    fn verify_output_written_rejects_missing_and_empty_files() {
+        let dir = unique_test_dir("verify_output");
+
+        let missing_path = dir.join("does-not-exist.avif");
+        assert!(verify_output_written(&missing_path).is_err());
+
+        let empty_path = dir.join("empty.avif");
+        fs::write(&empty_path, b"").unwrap();
+        assert!(verify_output_written(&empty_path).is_err());
+
+        let non_empty_path = dir.join("non-empty.avif");
+        fs::write(&non_empty_path, b"not actually a valid avif, just non-empty").unwrap();
+        assert!(verify_output_written(&non_empty_path).is_ok());
+    }
+}